@@ -1,18 +1,109 @@
-use std::fs::{File, OpenOptions};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
 use std::hash::{Hash, Hasher};
 use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::os::raw::c_int;
 use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Minimal `flock(2)` bindings. We only need a handful of constants and a
+// single function, so we avoid pulling in the `libc` crate for this.
+mod flock_ffi {
+    use std::os::raw::c_int;
+    use std::os::unix::io::RawFd;
+
+    extern "C" {
+        pub fn flock(fd: RawFd, operation: c_int) -> c_int;
+    }
+
+    pub const LOCK_SH: c_int = 1;
+    pub const LOCK_EX: c_int = 2;
+    pub const LOCK_UN: c_int = 8;
+    pub const LOCK_NB: c_int = 4;
+}
+
+// `FS_IOC_GETVERSION` reads a file's inode generation number. It's only
+// meaningful (and only wired up through `ioctl(2)`) on Linux; elsewhere we
+// just report a generation of `0`, which disables the generation check.
+#[cfg(target_os = "linux")]
+mod generation_ffi {
+    use std::os::raw::{c_int, c_ulong};
+
+    extern "C" {
+        pub fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+    }
+
+    pub const FS_IOC_GETVERSION: c_ulong = 0x8008_7601;
+}
+
+#[cfg(target_os = "linux")]
+fn file_generation(file: &File) -> u64 {
+    let mut generation: c_int = 0;
+    let ret = unsafe {
+        generation_ffi::ioctl(
+            file.as_raw_fd(),
+            generation_ffi::FS_IOC_GETVERSION,
+            &mut generation as *mut c_int,
+        )
+    };
+    if ret == 0 {
+        // The ioctl doesn't use negative values; reinterpret as unsigned
+        // before widening so we don't sign-extend.
+        generation as u32 as u64
+    } else {
+        // ENOTTY/EINVAL (ioctl not supported on this filesystem) and any
+        // other failure both fall back to dev/ino-only comparisons.
+        0
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn file_generation(_file: &File) -> u64 {
+    0
+}
 
 /// Low level key structure
 ///
-#[derive(Debug, Eq, PartialEq, Hash, Clone)]
+#[derive(Debug, Clone)]
 pub struct Key {
     /// device number
     dev: u64,
     /// inode
     ino: u64,
+    /// inode generation number, or `0` if unknown/unsupported
+    generation: u64,
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        if self.dev != other.dev || self.ino != other.ino {
+            return false;
+        }
+        // Only compare generations when both sides actually have one;
+        // otherwise we can't tell whether the filesystem supports it and
+        // fall back to today's dev/ino-only behavior.
+        if self.generation != 0 && other.generation != 0 {
+            self.generation == other.generation
+        } else {
+            true
+        }
+    }
+}
+
+impl Eq for Key {}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Deliberately excludes `generation`: two keys can compare equal even
+        // when only one of them has a nonzero generation (see `PartialEq`
+        // above), so hashing it in would violate the Hash/Eq contract.
+        self.dev.hash(state);
+        self.ino.hash(state);
+    }
 }
 
 #[derive(Debug)]
@@ -71,12 +162,14 @@ impl Handle {
 
     pub fn from_file(file: File) -> io::Result<Handle> {
         let md = file.metadata()?;
+        let generation = file_generation(&file);
         Ok(Handle {
             file: Some(file),
             is_std: false,
             key: Key {
                 dev: md.dev(),
                 ino: md.ino(),
+                generation,
             },
         })
     }
@@ -120,7 +213,451 @@ impl Handle {
         self.key.ino
     }
 
+    /// The inode generation number, or `0` if the underlying filesystem
+    /// doesn't support `FS_IOC_GETVERSION`.
+    pub fn generation(&self) -> u64 {
+        self.key.generation
+    }
+
     pub fn as_key(&self) -> Option<Key> {
         Some(self.key.clone())
     }
+
+    /// Acquires a shared (read) advisory lock on this handle's file,
+    /// blocking until it is available.
+    pub fn lock_shared<'a>(&'a self) -> io::Result<LockGuard<'a>> {
+        self.flock(flock_ffi::LOCK_SH)
+    }
+
+    /// Acquires an exclusive (write) advisory lock on this handle's file,
+    /// blocking until it is available.
+    pub fn lock_exclusive<'a>(&'a self) -> io::Result<LockGuard<'a>> {
+        self.flock(flock_ffi::LOCK_EX)
+    }
+
+    /// Like [`lock_shared`](Handle::lock_shared), but returns immediately
+    /// with `io::ErrorKind::WouldBlock` instead of blocking if the lock
+    /// isn't available.
+    pub fn try_lock_shared<'a>(&'a self) -> io::Result<LockGuard<'a>> {
+        self.flock(flock_ffi::LOCK_SH | flock_ffi::LOCK_NB)
+    }
+
+    /// Like [`lock_exclusive`](Handle::lock_exclusive), but returns
+    /// immediately with `io::ErrorKind::WouldBlock` instead of blocking if
+    /// the lock isn't available.
+    pub fn try_lock_exclusive<'a>(&'a self) -> io::Result<LockGuard<'a>> {
+        self.flock(flock_ffi::LOCK_EX | flock_ffi::LOCK_NB)
+    }
+
+    /// Releases the advisory lock held through this handle's descriptor.
+    /// Locks held on the same file through other handles are not affected.
+    ///
+    /// This is a manual alternative to letting a [`LockGuard`] go out of
+    /// scope, for callers that didn't keep the guard around.
+    pub fn unlock(&self) -> io::Result<()> {
+        let fd = self.as_file().as_raw_fd();
+        if unsafe { flock_ffi::flock(fd, flock_ffi::LOCK_UN) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    fn flock<'a>(&'a self, operation: c_int) -> io::Result<LockGuard<'a>> {
+        let fd = self.as_file().as_raw_fd();
+        if unsafe { flock_ffi::flock(fd, operation) } == 0 {
+            return Ok(LockGuard {
+                fd,
+                _marker: PhantomData,
+            });
+        }
+        // `io::Error::last_os_error()` already maps EAGAIN/EWOULDBLOCK to
+        // `ErrorKind::WouldBlock` using the target's real errno values, so
+        // there's no need to re-derive that mapping ourselves.
+        Err(io::Error::last_os_error())
+    }
+
+    /// Converts this handle into a cheaply `Clone`-able, reference-counted
+    /// handle that multiple owners can share.
+    ///
+    /// The underlying file description is kept open for as long as any
+    /// clone of the returned `SharedHandle` is alive. Identity (equality
+    /// and hashing) is still determined by the handle's `Key` (`dev`/`ino`,
+    /// plus `generation` when both sides have one).
+    pub fn into_shared(mut self) -> SharedHandle {
+        let file = self.file.take();
+        let is_std = self.is_std;
+        let key = self.key.clone();
+        // The fields we need have already been moved out above, so let
+        // `self`'s `Drop` impl (which would otherwise try to `unwrap()` a
+        // `None` file for std handles) not run at all.
+        mem::forget(self);
+        SharedHandle(Arc::new(SharedHandleInner {
+            file,
+            is_std,
+            key,
+        }))
+    }
+}
+
+/// The reference-counted inner state of a [`SharedHandle`].
+#[derive(Debug)]
+struct SharedHandleInner {
+    file: Option<File>,
+    // Same role as `Handle::is_std`: avoid closing the fd for a std stream
+    // when the last clone of the `SharedHandle` is dropped.
+    is_std: bool,
+    key: Key,
+}
+
+impl Drop for SharedHandleInner {
+    fn drop(&mut self) {
+        if self.is_std {
+            // unwrap() will not panic. Since we were able to open an
+            // std stream successfully, then `file` is guaranteed to be Some()
+            self.file.take().unwrap().into_raw_fd();
+        }
+    }
+}
+
+/// A reference-counted, cloneable handle to an open file.
+///
+/// Unlike [`Handle`], which uniquely owns its `File` and therefore can't
+/// implement `Clone` without risking closing the descriptor out from under
+/// another owner, `SharedHandle` wraps its state in an `Arc` so any number
+/// of owners can hold the same open file description cheaply.
+#[derive(Debug, Clone)]
+pub struct SharedHandle(Arc<SharedHandleInner>);
+
+impl Eq for SharedHandle {}
+
+impl PartialEq for SharedHandle {
+    fn eq(&self, other: &SharedHandle) -> bool {
+        self.0.key == other.0.key
+    }
+}
+
+impl Hash for SharedHandle {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.key.hash(state);
+    }
+}
+
+impl SharedHandle {
+    pub fn as_file(&self) -> &File {
+        // unwrap() will not panic. Since we were able to open the
+        // file successfully, then `file` is guaranteed to be Some()
+        self.0.file.as_ref().take().unwrap()
+    }
+
+    pub fn dev(&self) -> u64 {
+        self.0.key.dev
+    }
+
+    pub fn ino(&self) -> u64 {
+        self.0.key.ino
+    }
+
+    /// The inode generation number, or `0` if the underlying filesystem
+    /// doesn't support `FS_IOC_GETVERSION`.
+    pub fn generation(&self) -> u64 {
+        self.0.key.generation
+    }
+
+    pub fn as_key(&self) -> Option<Key> {
+        Some(self.0.key.clone())
+    }
+}
+
+impl AsRawFd for SharedHandle {
+    fn as_raw_fd(&self) -> RawFd {
+        self.as_file().as_raw_fd()
+    }
+}
+
+/// An RAII guard for an advisory lock taken via [`Handle::lock_shared`],
+/// [`Handle::lock_exclusive`], [`Handle::try_lock_shared`] or
+/// [`Handle::try_lock_exclusive`].
+///
+/// The lock is released when the guard is dropped.
+#[derive(Debug)]
+pub struct LockGuard<'a> {
+    fd: RawFd,
+    _marker: PhantomData<&'a Handle>,
+}
+
+impl<'a> Drop for LockGuard<'a> {
+    fn drop(&mut self) {
+        // Best effort: there's nowhere to report an error from `drop`.
+        // Callers that care about unlock failures should call
+        // `Handle::unlock` explicitly instead.
+        let _ = unsafe { flock_ffi::flock(self.fd, flock_ffi::LOCK_UN) };
+    }
+}
+
+/// A batch deduplication primitive for the common "which of these paths
+/// refer to the same file?" question, as faced by directory walkers that
+/// want to avoid revisiting a file reached via multiple (hard- or
+/// symlinked) paths.
+///
+/// Paths are grouped by their `Key` (`dev`/`ino`, plus `generation` where
+/// available). `insert_path` only stats each path via `fs::metadata`
+/// rather than keeping a `Handle` open (`insert_handle` records a `Handle`
+/// the caller already has open), so walking
+/// hundreds of thousands of files doesn't exhaust the process's file
+/// descriptor table.
+#[derive(Debug, Default)]
+pub struct HandleSet {
+    groups: HashMap<Key, Vec<PathBuf>>,
+}
+
+impl HandleSet {
+    pub fn new() -> HandleSet {
+        HandleSet {
+            groups: HashMap::new(),
+        }
+    }
+
+    /// Stats `path`, computes its `Key`, and records it in this set.
+    ///
+    /// Returns `true` if a path with the same `Key` was already present.
+    /// This never keeps a file descriptor open; the generation number
+    /// (see [`Handle::generation`]) is therefore always `0` for paths
+    /// inserted this way, so identity falls back to `dev`/`ino` alone.
+    pub fn insert_path<P: AsRef<Path>>(&mut self, path: P) -> io::Result<bool> {
+        let path = path.as_ref();
+        let md = fs::metadata(path)?;
+        let key = Key {
+            dev: md.dev(),
+            ino: md.ino(),
+            generation: 0,
+        };
+        Ok(self.insert(key, path.to_path_buf()))
+    }
+
+    /// Records an already-opened `Handle` under `path`, without closing it.
+    ///
+    /// Unlike `insert_path`, this can take advantage of a `Handle`'s
+    /// inode generation number, since it was read from an open descriptor
+    /// via `ioctl(2)`.
+    ///
+    /// Returns `true` if a handle with the same `Key` was already present.
+    pub fn insert_handle<P: Into<PathBuf>>(&mut self, handle: &Handle, path: P) -> bool {
+        self.insert(handle.key.clone(), path.into())
+    }
+
+    fn insert(&mut self, key: Key, path: PathBuf) -> bool {
+        let seen = self.groups.contains_key(&key);
+        self.groups.entry(key).or_default().push(path);
+        seen
+    }
+
+    /// Iterates over the groups of two or more paths found to refer to the
+    /// same file. Paths seen only once are omitted.
+    pub fn duplicates(&self) -> impl Iterator<Item = &[PathBuf]> {
+        self.groups
+            .values()
+            .map(|paths| paths.as_slice())
+            .filter(|paths| paths.len() > 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+    fn temp_path(name: &str) -> PathBuf {
+        let mut p = std::env::temp_dir();
+        p.push(format!(
+            "same-file-test-{}-{}-{}",
+            std::process::id(),
+            name,
+            NEXT_ID.fetch_add(1, Ordering::SeqCst)
+        ));
+        p
+    }
+
+    #[test]
+    fn try_lock_exclusive_returns_would_block_when_already_locked() {
+        let path = temp_path("lock");
+        File::create(&path).unwrap();
+
+        let held = Handle::from_path(&path).unwrap();
+        let contender = Handle::from_path(&path).unwrap();
+
+        let _guard = held.lock_exclusive().unwrap();
+        let err = contender.try_lock_exclusive().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn try_lock_exclusive_succeeds_once_unlocked() {
+        let path = temp_path("lock-released");
+        File::create(&path).unwrap();
+
+        let held = Handle::from_path(&path).unwrap();
+        let contender = Handle::from_path(&path).unwrap();
+
+        {
+            let _guard = held.lock_exclusive().unwrap();
+        }
+        assert!(contender.try_lock_exclusive().is_ok());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn handle_set_groups_hard_links_together() {
+        let original = temp_path("dup-original");
+        let link = temp_path("dup-link");
+        File::create(&original).unwrap();
+        fs::hard_link(&original, &link).unwrap();
+
+        let mut set = HandleSet::new();
+        assert!(!set.insert_path(&original).unwrap());
+        assert!(set.insert_path(&link).unwrap());
+
+        let groups: Vec<&[PathBuf]> = set.duplicates().collect();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].len(), 2);
+
+        fs::remove_file(&original).unwrap();
+        fs::remove_file(&link).unwrap();
+    }
+
+    #[test]
+    fn handle_set_insert_handle_groups_hard_links_together() {
+        let original = temp_path("dup-handle-original");
+        let link = temp_path("dup-handle-link");
+        File::create(&original).unwrap();
+        fs::hard_link(&original, &link).unwrap();
+
+        let original_handle = Handle::from_path(&original).unwrap();
+        let link_handle = Handle::from_path(&link).unwrap();
+
+        let mut set = HandleSet::new();
+        assert!(!set.insert_handle(&original_handle, original.clone()));
+        assert!(set.insert_handle(&link_handle, link.clone()));
+
+        let groups: Vec<&[PathBuf]> = set.duplicates().collect();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].len(), 2);
+
+        fs::remove_file(&original).unwrap();
+        fs::remove_file(&link).unwrap();
+    }
+
+    #[test]
+    fn handle_set_does_not_group_distinct_files() {
+        let a = temp_path("distinct-a");
+        let b = temp_path("distinct-b");
+        File::create(&a).unwrap();
+        File::create(&b).unwrap();
+
+        let mut set = HandleSet::new();
+        assert!(!set.insert_path(&a).unwrap());
+        assert!(!set.insert_path(&b).unwrap());
+        assert_eq!(set.duplicates().count(), 0);
+
+        fs::remove_file(&a).unwrap();
+        fs::remove_file(&b).unwrap();
+    }
+
+    #[test]
+    fn into_shared_keeps_std_fd_open_after_last_clone_drops() {
+        let shared = Handle::stdout().unwrap().into_shared();
+        let other = shared.clone();
+        drop(shared);
+        drop(other);
+
+        // fd 1 must still be a valid, open file descriptor: re-wrapping it
+        // and checking its metadata should succeed rather than failing
+        // with EBADF.
+        let still_open = unsafe { File::from_raw_fd(1) };
+        let result = still_open.metadata();
+        still_open.into_raw_fd();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn into_shared_closes_fd_only_after_last_clone_drops() {
+        let path = temp_path("shared-close");
+        File::create(&path).unwrap();
+
+        let shared = Handle::from_path(&path).unwrap().into_shared();
+        let other = shared.clone();
+        let fd = shared.as_raw_fd();
+
+        drop(shared);
+        // `other` still holds a clone, so the fd must remain open.
+        assert!(unsafe { libc_fcntl_getfd(fd) } >= 0);
+
+        drop(other);
+        // The last clone is gone, so the fd must now be closed.
+        assert!(unsafe { libc_fcntl_getfd(fd) } < 0);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn shared_handles_to_the_same_file_compare_equal() {
+        let path = temp_path("shared-eq");
+        File::create(&path).unwrap();
+
+        let a = Handle::from_path(&path).unwrap().into_shared();
+        let b = Handle::from_path(&path).unwrap().into_shared();
+        assert_eq!(a, b);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    // A tiny `fcntl(F_GETFD)` binding, used only to check whether a raw fd
+    // is still open without affecting it (unlike re-`dup`-ing or reading).
+    extern "C" {
+        #[link_name = "fcntl"]
+        fn libc_fcntl(fd: c_int, cmd: c_int) -> c_int;
+    }
+
+    unsafe fn libc_fcntl_getfd(fd: RawFd) -> c_int {
+        const F_GETFD: c_int = 1;
+        libc_fcntl(fd, F_GETFD)
+    }
+
+    fn key(dev: u64, ino: u64, generation: u64) -> Key {
+        Key {
+            dev,
+            ino,
+            generation,
+        }
+    }
+
+    #[test]
+    fn keys_with_equal_nonzero_generations_are_eq() {
+        assert_eq!(key(1, 2, 5), key(1, 2, 5));
+    }
+
+    #[test]
+    fn keys_with_differing_nonzero_generations_are_not_eq() {
+        assert_ne!(key(1, 2, 5), key(1, 2, 6));
+    }
+
+    #[test]
+    fn keys_with_one_zero_generation_fall_back_to_dev_ino() {
+        // A zero generation means "unknown" (e.g. stat-only mode, or a
+        // filesystem without FS_IOC_GETVERSION support), so it must not
+        // cause an otherwise-matching key to compare unequal.
+        assert_eq!(key(1, 2, 0), key(1, 2, 7));
+        assert_eq!(key(1, 2, 7), key(1, 2, 0));
+    }
+
+    #[test]
+    fn keys_with_different_dev_or_ino_are_never_eq() {
+        assert_ne!(key(1, 2, 5), key(9, 2, 5));
+        assert_ne!(key(1, 2, 5), key(1, 9, 5));
+    }
 }